@@ -0,0 +1,139 @@
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use syn::{Data, DataEnum, DataStruct, Fields};
+
+#[proc_macro_derive(RPCEncodable)]
+pub fn rpc_encodable(input: TokenStream) -> TokenStream {
+    let ast: syn::DeriveInput = syn::parse(input).unwrap();
+    let gen = match ast.data {
+        Data::Struct(ref s) => impl_encodable_struct(&ast, s),
+        Data::Enum(ref e) => impl_encodable_enum(&ast, e),
+        Data::Union(_) => panic!("RPCEncodable cannot be derived for unions"),
+    };
+    gen.into()
+}
+
+#[proc_macro_derive(RPCExtractable)]
+pub fn rpc_extractable(input: TokenStream) -> TokenStream {
+    let ast: syn::DeriveInput = syn::parse(input).unwrap();
+    let gen = match ast.data {
+        Data::Struct(ref s) => impl_extractable_struct(&ast, s),
+        Data::Enum(ref e) => impl_extractable_enum(&ast, e),
+        Data::Union(_) => panic!("RPCExtractable cannot be derived for unions"),
+    };
+    gen.into()
+}
+
+fn impl_encodable_struct(ast: &syn::DeriveInput, body: &DataStruct) -> proc_macro2::TokenStream {
+    let name = &ast.ident;
+    let encode_fields = match body.fields {
+        Fields::Named(ref fields) => {
+            let stmts = fields.named.iter().map(|f| {
+                let id = f.ident.as_ref().unwrap();
+                quote! { self.#id.encode(output)?; }
+            });
+            quote! { #(#stmts)* }
+        }
+        Fields::Unnamed(ref fields) => {
+            let stmts = (0..fields.unnamed.len()).map(|i| {
+                let idx = syn::Index::from(i);
+                quote! { self.#idx.encode(output)?; }
+            });
+            quote! { #(#stmts)* }
+        }
+        Fields::Unit => quote! {},
+    };
+
+    quote! {
+        impl krpc_mars::codec::RPCEncodable for #name {
+            fn encode(&self, output: &mut protobuf::CodedOutputStream) -> Result<(), protobuf::ProtobufError> {
+                #encode_fields
+                output.flush()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn impl_extractable_struct(ast: &syn::DeriveInput, body: &DataStruct) -> proc_macro2::TokenStream {
+    let name = &ast.ident;
+    let build = match body.fields {
+        Fields::Named(ref fields) => {
+            let stmts = fields.named.iter().map(|f| {
+                let id = f.ident.as_ref().unwrap();
+                quote! { #id: krpc_mars::codec::RPCExtractable::extract_value(client, input)?, }
+            });
+            quote! { #name { #(#stmts)* } }
+        }
+        Fields::Unnamed(ref fields) => {
+            let stmts = (0..fields.unnamed.len()).map(|_| {
+                quote! { krpc_mars::codec::RPCExtractable::extract_value(client, input)?, }
+            });
+            quote! { #name ( #(#stmts)* ) }
+        }
+        Fields::Unit => quote! { #name },
+    };
+
+    // A field-less type reads nothing, so underscore the params to keep the
+    // derive warning-clean under `clippy -D warnings` downstream.
+    let (client_param, input_param) = if has_fields(&body.fields) {
+        (quote! { client }, quote! { input })
+    } else {
+        (quote! { _client }, quote! { _input })
+    };
+
+    quote! {
+        impl krpc_mars::codec::RPCExtractable for #name {
+            fn extract_value(#client_param: &krpc_mars::RPCClient, #input_param: &mut protobuf::CodedInputStream) -> Result<Self, protobuf::ProtobufError> {
+                Ok(#build)
+            }
+        }
+    }
+}
+
+fn has_fields(fields: &Fields) -> bool {
+    match *fields {
+        Fields::Named(ref f) => !f.named.is_empty(),
+        Fields::Unnamed(ref f) => !f.unnamed.is_empty(),
+        Fields::Unit => false,
+    }
+}
+
+fn impl_encodable_enum(ast: &syn::DeriveInput, _body: &DataEnum) -> proc_macro2::TokenStream {
+    let name = &ast.ident;
+    quote! {
+        impl krpc_mars::codec::RPCEncodable for #name {
+            fn encode(&self, output: &mut protobuf::CodedOutputStream) -> Result<(), protobuf::ProtobufError> {
+                output.write_sint32_no_tag(*self as i32)?;
+                output.flush()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn impl_extractable_enum(ast: &syn::DeriveInput, body: &DataEnum) -> proc_macro2::TokenStream {
+    let name = &ast.ident;
+    let arms = body.variants.iter().map(|v| {
+        let id = &v.ident;
+        quote! { x if x == #name::#id as i32 => #name::#id, }
+    });
+    quote! {
+        impl krpc_mars::codec::RPCExtractable for #name {
+            fn extract_value(_client: &krpc_mars::RPCClient, input: &mut protobuf::CodedInputStream) -> Result<Self, protobuf::ProtobufError> {
+                let discriminant = input.read_sint32()?;
+                let value = match discriminant {
+                    #(#arms)*
+                    _ => return Err(protobuf::ProtobufError::WireError(
+                        protobuf::error::WireError::InvalidEnumValue(discriminant))),
+                };
+                Ok(value)
+            }
+        }
+    }
+}
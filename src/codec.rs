@@ -3,12 +3,22 @@ use rpcfailure::RPCFailure;
 use protobuf;
 use protobuf::Message;
 
+use std::fmt;
 use std::io::Read;
 use std::hash::Hash;
 use std::collections::HashSet;
 use std::collections::HashMap;
 
 
+/// Wrapper for kRPC's first-class `bytes` type: a single length-delimited blob
+/// rather than a `List` of individually-tagged `uint8` items.
+///
+/// A bare `Vec<u8>` resolves to the generic `Vec<T>` collection impl, which is
+/// wrong on the wire for `bytes`-typed arguments and return values; wrap those
+/// payloads in `Bytes` so they round-trip correctly.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Bytes(pub Vec<u8>);
+
 pub trait RPCExtractable: Sized {
     fn extract_value(client: &super::RPCClient, input: &mut protobuf::CodedInputStream) -> Result<Self, protobuf::ProtobufError>;
 }
@@ -67,19 +77,35 @@ impl RPCExtractable for String {
     }
 }
 
+// Reads the next length-delimited field off `input` and extracts its body in
+// place behind a pushed limit, so no intermediate `Vec<u8>` is materialized
+// for the element. Returns the field number alongside the extracted value.
+fn extract_delimited<T>(client: &super::RPCClient, input: &mut protobuf::CodedInputStream) -> Result<(u32, T), protobuf::ProtobufError>
+    where T: RPCExtractable
+{
+    let (field, _wire_type) = input.read_tag_unpack()?;
+    let len = input.read_raw_varint64()?;
+    let old_limit = input.push_limit(len)?;
+    let value = RPCExtractable::extract_value(client, input)?;
+    input.pop_limit(old_limit);
+    Ok((field, value))
+}
+
+impl RPCExtractable for Bytes {
+    fn extract_value(_client: &super::RPCClient, input: &mut protobuf::CodedInputStream) -> Result<Self, protobuf::ProtobufError> {
+        input.read_bytes().map(Bytes)
+    }
+}
+
 impl<T> RPCExtractable for Vec<T>
     where T: RPCExtractable
 {
     fn extract_value(client: &super::RPCClient, input: &mut protobuf::CodedInputStream) -> Result<Self, protobuf::ProtobufError> {
-        let mut m = krpc::List::new();
-        m.merge_from(input)?;
-
-        let mut v = Vec::with_capacity(m.items.len());
-        for item in &m.items {
-            let mut i = protobuf::CodedInputStream::from_bytes(&item);
-            v.push(RPCExtractable::extract_value(client, &mut i)?);
+        let mut v = Vec::new();
+        while !input.eof()? {
+            let (_field, item) = extract_delimited(client, input)?;
+            v.push(item);
         }
-
         Ok(v)
     }
 }
@@ -88,15 +114,11 @@ impl<T> RPCExtractable for HashSet<T>
     where T: RPCExtractable + Hash + Eq,
 {
     fn extract_value(client: &super::RPCClient, input: &mut protobuf::CodedInputStream) -> Result<Self, protobuf::ProtobufError> {
-        let mut m = krpc::Set::new();
-        m.merge_from(input)?;
-
-        let mut s = HashSet::with_capacity(m.items.len());
-        for item in &m.items {
-            let mut i = protobuf::CodedInputStream::from_bytes(&item);
-            s.insert(RPCExtractable::extract_value(client, &mut i)?);
+        let mut s = HashSet::new();
+        while !input.eof()? {
+            let (_field, item) = extract_delimited(client, input)?;
+            s.insert(item);
         }
-
         Ok(s)
     }
 }
@@ -106,18 +128,37 @@ impl<T, U> RPCExtractable for HashMap<T, U>
           U: RPCExtractable
 {
     fn extract_value(client: &super::RPCClient, input: &mut protobuf::CodedInputStream) -> Result<Self, protobuf::ProtobufError> {
-        let mut m = krpc::Dictionary::new();
-        m.merge_from(input)?;
-
-        let mut h = HashMap::with_capacity(m.entries.len());
-        for entry in &m.entries {
-            let mut i_k = protobuf::CodedInputStream::from_bytes(&entry.key);
-            let mut i_v = protobuf::CodedInputStream::from_bytes(&entry.value);
-            let key = RPCExtractable::extract_value(client, &mut i_k)?;
-            let val = RPCExtractable::extract_value(client, &mut i_v)?;
-            h.insert(key, val);
+        let mut h = HashMap::new();
+        while !input.eof()? {
+            // Each dictionary entry is itself a length-delimited sub-message
+            // carrying a key (field 1) and a value (field 2).
+            let (_entry_field, _wire) = input.read_tag_unpack()?;
+            let entry_len = input.read_raw_varint64()?;
+            let entry_limit = input.push_limit(entry_len)?;
+
+            let mut key = None;
+            let mut val = None;
+            while !input.eof()? {
+                let (field, _wire_type) = input.read_tag_unpack()?;
+                let len = input.read_raw_varint64()?;
+                let old_limit = input.push_limit(len)?;
+                match field {
+                    1 => key = Some(RPCExtractable::extract_value(client, input)?),
+                    2 => val = Some(RPCExtractable::extract_value(client, input)?),
+                    _ => { input.read_raw_bytes(len as u32)?; }
+                }
+                input.pop_limit(old_limit);
+            }
+            input.pop_limit(entry_limit);
+
+            match (key, val) {
+                (Some(key), Some(val)) => { h.insert(key, val); }
+                // A dictionary entry is malformed if it is missing either half;
+                // the old `Dictionary::merge_from` path always materialized an
+                // entry, so surface this rather than silently shrinking the map.
+                _ => return Err(protobuf::ProtobufError::WireError(protobuf::error::WireError::IncompleteMap)),
+            }
         }
-
         Ok(h)
     }
 }
@@ -224,19 +265,31 @@ impl RPCEncodable for String {
     }
 }
 
+impl RPCEncodable for Bytes {
+    fn encode(&self, output: &mut protobuf::CodedOutputStream) -> Result<(), protobuf::ProtobufError> {
+        output.write_bytes_no_tag(&self.0)
+    }
+}
+
 impl<T> RPCEncodable for Vec<T>
     where T: RPCEncodable
 {
     fn encode(&self, output: &mut protobuf::CodedOutputStream) -> Result<(), protobuf::ProtobufError> {
-        let mut v = protobuf::RepeatedField::<Vec<u8>>::new();
+        // Write each element's body directly as a `List.items` (field 1) entry,
+        // reusing a single scratch buffer for the element bytes rather than
+        // allocating a fresh `Vec<u8>` per item and a `RepeatedField` on top.
+        let mut scratch = Vec::new();
         for e in self {
-            v.push(e.encode_to_bytes()?);
+            scratch.clear();
+            {
+                let mut element = protobuf::CodedOutputStream::new(&mut scratch);
+                e.encode(&mut element)?;
+                element.flush()?;
+            }
+            output.write_tag(1, protobuf::stream::wire_format::WireType::WireTypeLengthDelimited)?;
+            output.write_raw_varint32(scratch.len() as u32)?;
+            output.write_raw_bytes(&scratch)?;
         }
-
-        let mut l = krpc::List::new();
-        l.set_items(v);
-
-        l.write_to(output)?;
         output.flush()?;
 
         Ok(())
@@ -288,13 +341,202 @@ impl<T, U, V, W> RPCEncodable for (T, U, V, W)
     }
 }
 
-pub fn read_message<M>(sock: &mut Read) -> Result<M, protobuf::ProtobufError>
+/// Default upper bound, in bytes, on a single length-delimited frame.
+///
+/// A hostile or buggy server can announce an arbitrary length prefix; capping
+/// it keeps a bad frame from driving an unbounded allocation. `read_message`
+/// always applies this cap; legitimate responses that exceed it can be read
+/// with a higher limit by calling `read_message_bounded` directly.
+pub const MAX_BUF_SIZE: usize = 16 * 1024 * 1024;
+
+/// A base-128 varint is at most 10 bytes wide for a 64-bit value; anything
+/// longer is a malformed (or hostile) length prefix and must be rejected before
+/// the shift overflows.
+const MAX_VARINT_BYTES: usize = 10;
+
+/// Error returned while reading a length-delimited frame off a socket.
+///
+/// Distinct from `protobuf::ProtobufError` so that a frame rejected for
+/// exceeding the configured size cap, or a malformed length prefix, can be
+/// matched on explicitly rather than surfacing as a generic I/O error.
+#[derive(Debug)]
+pub enum ReadError {
+    /// The declared frame length exceeded the configured maximum.
+    FrameTooLarge { len: usize, max: usize },
+    /// The varint length prefix did not terminate within `MAX_VARINT_BYTES`.
+    MalformedLength,
+    /// The socket closed before the whole frame had been read.
+    UnexpectedEof,
+    Io(::std::io::Error),
+    Protobuf(protobuf::ProtobufError),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReadError::FrameTooLarge { len, max } => write!(f, "frame length {} exceeds maximum {}", len, max),
+            ReadError::MalformedLength => write!(f, "malformed length prefix"),
+            ReadError::UnexpectedEof => write!(f, "socket closed before frame was complete"),
+            ReadError::Io(ref e) => write!(f, "{}", e),
+            ReadError::Protobuf(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl ::std::error::Error for ReadError {
+    fn description(&self) -> &str {
+        "error reading a length-delimited frame"
+    }
+}
+
+impl From<::std::io::Error> for ReadError {
+    fn from(e: ::std::io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl From<protobuf::ProtobufError> for ReadError {
+    fn from(e: protobuf::ProtobufError) -> Self {
+        ReadError::Protobuf(e)
+    }
+}
+
+// Keep the public read path funnelling into `RPCFailure` as it did before
+// `read_message` grew its own error type: callers that used `?` to turn a
+// read error into an `RPCFailure` continue to compile.
+impl From<ReadError> for RPCFailure {
+    fn from(e: ReadError) -> Self {
+        match e {
+            ReadError::Protobuf(e) => RPCFailure::ProtobufFailure(e),
+            other => RPCFailure::ProtobufFailure(protobuf::ProtobufError::IoError(
+                ::std::io::Error::new(::std::io::ErrorKind::InvalidData, other.to_string()),
+            )),
+        }
+    }
+}
+
+fn read_varint(sock: &mut Read) -> Result<u64, ReadError> {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    for _ in 0..MAX_VARINT_BYTES {
+        let mut byte = [0u8; 1];
+        sock.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+    Err(ReadError::MalformedLength)
+}
+
+pub fn read_message<M>(sock: &mut Read) -> Result<M, ReadError>
+    where M: protobuf::Message + protobuf::MessageStatic
+{
+    read_message_bounded(sock, MAX_BUF_SIZE)
+}
+
+/// Reads one length-delimited frame, rejecting any whose declared length
+/// exceeds `max_buf_size` before allocating for it.
+///
+/// The length prefix is read first, then exactly that many bytes are pulled
+/// into a buffer in chunks so that partial socket reads accumulate rather than
+/// error out. Only the fully-buffered frame is handed to the parser. A caller
+/// expecting a large legitimate response can pass a higher limit than
+/// `MAX_BUF_SIZE` here; the client itself holds no per-client cap, and
+/// `read_message` always uses `MAX_BUF_SIZE`.
+pub fn read_message_bounded<M>(sock: &mut Read, max_buf_size: usize) -> Result<M, ReadError>
     where M: protobuf::Message + protobuf::MessageStatic
 {
-    let mut input_stream = protobuf::CodedInputStream::new(sock);
-    protobuf::parse_length_delimited_from::<M>(&mut input_stream)
+    let len = read_varint(sock)? as usize;
+    if len > max_buf_size {
+        return Err(ReadError::FrameTooLarge { len: len, max: max_buf_size });
+    }
+
+    let mut buf = vec![0u8; len];
+    let mut read = 0;
+    while read < len {
+        match sock.read(&mut buf[read..]) {
+            Ok(0) => return Err(ReadError::UnexpectedEof),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(ReadError::Io(e)),
+        }
+    }
+
+    Ok(protobuf::parse_from_bytes::<M>(&buf)?)
+}
+
+
+#[cfg(feature = "async")]
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Reads one length-delimited protobuf frame off an async socket.
+///
+/// The varint length prefix is read byte-by-byte without blocking, then the
+/// announced number of bytes are pulled into a buffer and parsed. Only the
+/// socket I/O is async; parsing still runs over an in-memory
+/// `CodedInputStream`.
+#[cfg(feature = "async")]
+pub async fn read_message_async<M>(sock: &mut (impl AsyncRead + Unpin)) -> Result<M, ReadError>
+    where M: protobuf::Message + protobuf::MessageStatic
+{
+    read_message_async_bounded(sock, MAX_BUF_SIZE).await
+}
+
+/// Async counterpart of `read_message_bounded`: reads the length prefix, rejects
+/// frames exceeding `max_buf_size` before allocating, and accumulates the body
+/// across partial reads. Shares the same hardening as the blocking path so the
+/// two transports cannot diverge on the hostile-server threat model.
+#[cfg(feature = "async")]
+pub async fn read_message_async_bounded<M>(sock: &mut (impl AsyncRead + Unpin), max_buf_size: usize) -> Result<M, ReadError>
+    where M: protobuf::Message + protobuf::MessageStatic
+{
+    let mut len: u64 = 0;
+    let mut shift = 0;
+    let mut terminated = false;
+    for _ in 0..MAX_VARINT_BYTES {
+        let mut byte = [0u8; 1];
+        sock.read_exact(&mut byte).await?;
+        len |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            terminated = true;
+            break;
+        }
+        shift += 7;
+    }
+    if !terminated {
+        return Err(ReadError::MalformedLength);
+    }
+
+    let len = len as usize;
+    if len > max_buf_size {
+        return Err(ReadError::FrameTooLarge { len: len, max: max_buf_size });
+    }
+
+    let mut buf = vec![0u8; len];
+    let mut read = 0;
+    while read < len {
+        let n = sock.read(&mut buf[read..]).await?;
+        if n == 0 {
+            return Err(ReadError::UnexpectedEof);
+        }
+        read += n;
+    }
+
+    Ok(protobuf::parse_from_bytes::<M>(&buf)?)
 }
 
+/// Sends one length-delimited protobuf frame over an async socket.
+#[cfg(feature = "async")]
+pub async fn write_message_async<M>(sock: &mut (impl AsyncWrite + Unpin), message: &M) -> Result<(), protobuf::ProtobufError>
+    where M: protobuf::Message
+{
+    let bytes = message.write_length_delimited_to_bytes()?;
+    sock.write_all(&bytes).await.map_err(protobuf::ProtobufError::IoError)?;
+    sock.flush().await.map_err(protobuf::ProtobufError::IoError)?;
+    Ok(())
+}
 
 pub fn extract_single_result<T>(client: &super::RPCClient, response: &krpc::Response) -> Result<T, RPCFailure>
     where T: RPCExtractable
@@ -318,3 +560,212 @@ pub fn extract_result<T>(client: &super::RPCClient, proc_result: &krpc::Procedur
         RPCExtractable::extract_value(client, &mut input).map_err(RPCFailure::ProtobufFailure)
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::Message;
+    use std::cell::RefCell;
+    use std::collections::{HashMap, HashSet};
+    use std::io::{self, Read};
+    use std::net::{TcpListener, TcpStream};
+
+    // A client is only ever threaded through `extract_value` and never read by
+    // the primitive/collection extractors, so a socket pointed at a throwaway
+    // loopback listener is enough to satisfy the signature in tests.
+    fn dummy_client() -> ::RPCClient {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sock = TcpStream::connect(addr).unwrap();
+        ::RPCClient {
+            sock: RefCell::new(sock),
+            client_id: Vec::new(),
+        }
+    }
+
+    // Hands out its data in fixed-size chunks, so the accumulation loop is
+    // exercised against short socket reads rather than one big read.
+    struct ChunkedReader {
+        data: Vec<u8>,
+        pos: usize,
+        chunk: usize,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let remaining = self.data.len() - self.pos;
+            if remaining == 0 {
+                return Ok(0);
+            }
+            let n = remaining.min(self.chunk).min(buf.len());
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    // Prepends a base-128 varint length prefix to `body`.
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut len = body.len() as u64;
+        loop {
+            let mut b = (len & 0x7f) as u8;
+            len >>= 7;
+            if len != 0 {
+                b |= 0x80;
+            }
+            out.push(b);
+            if len == 0 {
+                break;
+            }
+        }
+        out.extend_from_slice(body);
+        out
+    }
+
+    #[test]
+    fn reads_frame_across_partial_reads() {
+        let body = vec![1i32, -2, 3].encode_to_bytes().unwrap();
+        let mut r = ChunkedReader { data: frame(&body), pos: 0, chunk: 1 };
+        let msg: krpc::List = read_message(&mut r).unwrap();
+        assert_eq!(msg.items.len(), 3);
+    }
+
+    #[test]
+    fn rejects_oversized_frame() {
+        let mut r = ChunkedReader { data: frame(&vec![0u8; 64]), pos: 0, chunk: 8 };
+        match read_message_bounded::<krpc::List>(&mut r, 16) {
+            Err(ReadError::FrameTooLarge { len, max }) => {
+                assert_eq!(len, 64);
+                assert_eq!(max, 16);
+            }
+            other => panic!("expected FrameTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_eof_mid_frame() {
+        let mut data = frame(&vec![0u8; 32]);
+        data.truncate(data.len() - 10);
+        let mut r = ChunkedReader { data: data, pos: 0, chunk: 4 };
+        match read_message_bounded::<krpc::List>(&mut r, 1024) {
+            Err(ReadError::UnexpectedEof) => {}
+            other => panic!("expected UnexpectedEof, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_length_prefix() {
+        let mut r = ChunkedReader { data: vec![0x80u8; 11], pos: 0, chunk: 1 };
+        match read_message_bounded::<krpc::List>(&mut r, 1024) {
+            Err(ReadError::MalformedLength) => {}
+            other => panic!("expected MalformedLength, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn vec_encodes_in_place_as_list_items() {
+        let v: Vec<i32> = vec![1, -2, 3];
+        let bytes = v.encode_to_bytes().unwrap();
+
+        let list = krpc::List::parse_from_bytes(&bytes).unwrap();
+        assert_eq!(list.items.len(), 3);
+
+        let decoded: Vec<i32> = list.items.iter().map(|item| {
+            let mut input = protobuf::CodedInputStream::from_bytes(item);
+            input.read_sint32().unwrap()
+        }).collect();
+        assert_eq!(decoded, v);
+    }
+
+    #[test]
+    fn empty_vec_encodes_to_empty_list() {
+        let v: Vec<i32> = Vec::new();
+        let bytes = v.encode_to_bytes().unwrap();
+        assert!(krpc::List::parse_from_bytes(&bytes).unwrap().items.is_empty());
+    }
+
+    #[test]
+    fn bytes_encodes_as_single_length_delimited_blob() {
+        let payload = vec![0u8, 1, 2, 255];
+        let bytes = Bytes(payload.clone()).encode_to_bytes().unwrap();
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        assert_eq!(input.read_bytes().unwrap(), payload);
+    }
+
+    #[test]
+    fn vec_decode_roundtrips() {
+        let client = dummy_client();
+        let v: Vec<i32> = vec![1, -2, 3, 0, 12345];
+        let bytes = v.encode_to_bytes().unwrap();
+
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let decoded: Vec<i32> = RPCExtractable::extract_value(&client, &mut input).unwrap();
+        assert_eq!(decoded, v);
+    }
+
+    #[test]
+    fn empty_vec_decodes_to_empty() {
+        let client = dummy_client();
+        let bytes = (Vec::<i32>::new()).encode_to_bytes().unwrap();
+
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let decoded: Vec<i32> = RPCExtractable::extract_value(&client, &mut input).unwrap();
+        assert!(decoded.is_empty());
+    }
+
+    #[test]
+    fn nested_vec_decode_roundtrips() {
+        let client = dummy_client();
+        let vv: Vec<Vec<i32>> = vec![vec![1, 2], vec![], vec![3, 4, 5]];
+        let bytes = vv.encode_to_bytes().unwrap();
+
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let decoded: Vec<Vec<i32>> = RPCExtractable::extract_value(&client, &mut input).unwrap();
+        assert_eq!(decoded, vv);
+    }
+
+    #[test]
+    fn hashset_decode_roundtrips() {
+        let client = dummy_client();
+        let s: HashSet<i32> = vec![1, 7, 42].into_iter().collect();
+        // A Set shares the `List.items` wire layout, so we can build it through
+        // the Vec encoder.
+        let bytes = vec![1i32, 7, 42].encode_to_bytes().unwrap();
+
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let decoded: HashSet<i32> = RPCExtractable::extract_value(&client, &mut input).unwrap();
+        assert_eq!(decoded, s);
+    }
+
+    #[test]
+    fn hashmap_decode_roundtrips() {
+        let client = dummy_client();
+
+        let mut entry = krpc::DictionaryEntry::new();
+        entry.set_key(5i32.encode_to_bytes().unwrap());
+        entry.set_value(7i32.encode_to_bytes().unwrap());
+        let mut dict = krpc::Dictionary::new();
+        dict.entries.push(entry);
+        let bytes = dict.write_to_bytes().unwrap();
+
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let decoded: HashMap<i32, i32> = RPCExtractable::extract_value(&client, &mut input).unwrap();
+        assert_eq!(decoded.get(&5), Some(&7));
+    }
+
+    #[test]
+    fn hashmap_rejects_entry_missing_value() {
+        let client = dummy_client();
+        // A single dictionary entry (field 1) carrying only a key (field 1,
+        // value 5) and no value field.
+        let bytes = vec![0x0A, 0x03, 0x0A, 0x01, 0x0A];
+
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        match HashMap::<i32, i32>::extract_value(&client, &mut input) {
+            Err(protobuf::ProtobufError::WireError(protobuf::error::WireError::IncompleteMap)) => {}
+            other => panic!("expected IncompleteMap, got {:?}", other),
+        }
+    }
+}
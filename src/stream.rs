@@ -0,0 +1,108 @@
+use krpc;
+use codec::{self, RPCExtractable};
+use rpcfailure::RPCFailure;
+use protobuf::Message;
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+/// A handle onto a single kRPC stream.
+///
+/// Streams are registered once with the stream server and then pushed to the
+/// client on every physics frame. The latest raw value received for this
+/// stream's id is kept in the shared update map; `value` extracts it through
+/// the usual `RPCExtractable` machinery.
+pub struct Stream<T: RPCExtractable> {
+    stream_id: u64,
+    updates: Arc<Mutex<HashMap<u64, krpc::ProcedureResult>>>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: RPCExtractable> Stream<T> {
+    /// Returns the most recent value pushed for this stream, or `None` if no
+    /// update has been received yet.
+    pub fn value(&self, client: &super::RPCClient) -> Option<Result<T, RPCFailure>> {
+        let updates = self.updates.lock().unwrap();
+        updates.get(&self.stream_id).map(|result| codec::extract_result(client, result))
+    }
+
+    /// The stream server id this handle is registered under.
+    pub fn id(&self) -> u64 {
+        self.stream_id
+    }
+}
+
+/// Connects to the stream server port and dispatches incoming `StreamUpdate`
+/// messages to the registered stream handles.
+pub struct StreamClient {
+    sock: TcpStream,
+    updates: Arc<Mutex<HashMap<u64, krpc::ProcedureResult>>>,
+}
+
+impl StreamClient {
+    /// Opens the second "stream server" connection and performs its handshake.
+    ///
+    /// The stream port expects a `ConnectionRequest` of type `STREAM` carrying
+    /// the id handed back by the main RPC connection's handshake; the server
+    /// replies with a `ConnectionResponse` whose status must be `OK` before any
+    /// updates are pushed.
+    pub fn connect<A: ToSocketAddrs>(addr: A, client_id: &[u8]) -> Result<Self, codec::ReadError> {
+        let mut sock = TcpStream::connect(addr)?;
+
+        let mut request = krpc::ConnectionRequest::new();
+        request.set_field_type(krpc::ConnectionRequest_Type::STREAM);
+        request.set_client_identifier(client_id.to_vec());
+        request.write_length_delimited_to_writer(&mut sock)?;
+
+        let response: krpc::ConnectionResponse = codec::read_message(&mut sock)?;
+        if response.get_status() != krpc::ConnectionResponse_Status::OK {
+            return Err(codec::ReadError::Io(::std::io::Error::new(
+                ::std::io::ErrorKind::PermissionDenied,
+                format!("stream server rejected connection: {}", response.get_message()),
+            )));
+        }
+
+        Ok(StreamClient {
+            sock: sock,
+            updates: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Binds a typed handle to a stream id that has already been added on the
+    /// server (via the main client's `AddStream` procedure), and starts
+    /// tracking updates for it.
+    pub fn stream<T: RPCExtractable>(&self, stream_id: u64) -> Stream<T> {
+        // `update()` inserts the real `ProcedureResult` once the first update for
+        // this id arrives; until then `value()` must report `None`, so we do not
+        // seed a placeholder here.
+        Stream {
+            stream_id: stream_id,
+            updates: Arc::clone(&self.updates),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Runs the continuous read loop, blocking until the socket is closed.
+    ///
+    /// Each iteration pulls one length-delimited `StreamUpdate` frame and
+    /// dispatches every decoded `ProcedureResult` to the id it is keyed under,
+    /// mirroring the background decode loop of msgpack-rpc clients.
+    pub fn run(&mut self) -> Result<(), codec::ReadError> {
+        loop {
+            self.update()?;
+        }
+    }
+
+    /// Reads and applies a single `StreamUpdate` frame. Exposed for callers that
+    /// want to drive the loop themselves (e.g. one update per rendered frame).
+    pub fn update(&mut self) -> Result<(), codec::ReadError> {
+        let update: krpc::StreamUpdate = codec::read_message(&mut self.sock)?;
+        let mut updates = self.updates.lock().unwrap();
+        for mut result in update.results.into_iter() {
+            updates.insert(result.get_id(), result.take_result());
+        }
+        Ok(())
+    }
+}